@@ -2,6 +2,7 @@
 
 //! See `c/blake.h` in the BLAKE3 repository.
 
+pub const BLAKE3_KEY_LEN:   usize = 32;
 pub const BLAKE3_BLOCK_LEN: usize = 64;
 pub const BLAKE3_MAX_DEPTH: usize = 54;
 pub const BLAKE3_OUT_LEN:   usize = 32;
@@ -31,6 +32,14 @@ extern "C"
 {
     pub fn blake3_version() -> *const libc::c_char;
     pub fn blake3_hasher_init(this: *mut blake3_hasher);
+    pub fn blake3_hasher_init_keyed(
+        this: *mut blake3_hasher,
+        key:  *const u8,
+    );
+    pub fn blake3_hasher_init_derive_key(
+        this:    *mut blake3_hasher,
+        context: *const libc::c_char,
+    );
     pub fn blake3_hasher_update(
         this:      *mut blake3_hasher,
         input:     *const libc::c_void,
@@ -41,6 +50,12 @@ extern "C"
         out:     *mut u8,
         out_len: libc::size_t,
     );
+    pub fn blake3_hasher_finalize_seek(
+        this:    *mut blake3_hasher,
+        seek:    u64,
+        out:     *mut u8,
+        out_len: libc::size_t,
+    );
 }
 
 #[cfg(test)]
@@ -61,5 +76,15 @@ mod tests
              Please check that this crate still matches the C interface, \
              and change the version number in this failed assertion."
         );
+
+        // The keyed/derive-key/XOF entry points are part of the 1.3.1 C
+        // interface. Reference their addresses so a future version that
+        // dropped or renamed any of them fails to link rather than silently
+        // leaving the higher-level wrappers unusable.
+        let _symbols: &[*const ()] = &[
+            blake3_hasher_init_keyed      as *const (),
+            blake3_hasher_init_derive_key as *const (),
+            blake3_hasher_finalize_seek   as *const (),
+        ];
     }
 }
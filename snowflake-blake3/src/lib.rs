@@ -9,7 +9,7 @@
 
 use {
     snowflake_blake3_sys as sys,
-    std::mem::MaybeUninit,
+    std::{ffi::CString, mem::MaybeUninit},
 };
 
 /// BLAKE3 hasher state.
@@ -32,6 +32,41 @@ impl Blake3
         }
     }
 
+    /// Create a new keyed BLAKE3 hasher state.
+    ///
+    /// The output depends on both the key and the input, which gives a
+    /// message authentication code and keeps cache keys from different
+    /// secrets distinct.
+    pub fn new_keyed(key: [u8; 32]) -> Self
+    {
+        let mut inner = MaybeUninit::uninit();
+        unsafe {
+            sys::blake3_hasher_init_keyed(
+                /* self */ inner.as_mut_ptr(),
+                /* key  */ key.as_ptr(),
+            );
+            Self{inner: inner.assume_init()}
+        }
+    }
+
+    /// Create a new key-derivation BLAKE3 hasher state.
+    ///
+    /// The `context` string provides domain separation, so cache keys for
+    /// different action kinds cannot collide even with identical input.
+    pub fn new_derive_key(context: &str) -> Self
+    {
+        let context = CString::new(context)
+            .expect("Context string must not contain NULs");
+        let mut inner = MaybeUninit::uninit();
+        unsafe {
+            sys::blake3_hasher_init_derive_key(
+                /* self    */ inner.as_mut_ptr(),
+                /* context */ context.as_ptr(),
+            );
+            Self{inner: inner.assume_init()}
+        }
+    }
+
     /// Update the hasher state with new input.
     pub fn update(&mut self, input: &[u8])
     {
@@ -57,6 +92,23 @@ impl Blake3
             out.assume_init()
         }
     }
+
+    /// Extract arbitrary-length output from the hasher state.
+    ///
+    /// BLAKE3 is an extendable-output function: `out` is filled with as many
+    /// bytes as it is long, letting callers derive several independent
+    /// subkeys from a single hash state.
+    pub fn finish_xof(&self, out: &mut [u8])
+    {
+        unsafe {
+            sys::blake3_hasher_finalize_seek(
+                /* self    */ &self.inner as *const _ as *mut _,
+                /* seek    */ 0,
+                /* out     */ out.as_mut_ptr(),
+                /* out_len */ out.len(),
+            );
+        }
+    }
 }
 
 #[cfg(test)]
@@ -78,4 +130,20 @@ mod tests
 
         assert_eq!(&actual, expected);
     }
+
+    #[test]
+    fn xof_matches_finish()
+    {
+        // The first 32 bytes of the XOF output equal the fixed digest.
+        let mut a = Blake3::new();
+        a.update(b"Hello, world!");
+
+        let mut b = Blake3::new();
+        b.update(b"Hello, world!");
+
+        let mut out = [0u8; 64];
+        a.finish_xof(&mut out);
+
+        assert_eq!(out[.. 32], b.finish());
+    }
 }
@@ -4,10 +4,11 @@
 
 use std::{
     ffi::{CStr, CString},
-    io::Result,
-    mem::transmute,
+    io::{Error, ErrorKind, Result},
+    mem::{MaybeUninit, transmute},
     ops::Deref,
-    ptr::null_mut,
+    ptr::{copy_nonoverlapping, null_mut},
+    slice,
 };
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -159,7 +160,7 @@ impl<'a> WithCStr for &'a str
     fn with_cstr<F, R>(self, f: F) -> Result<R>
         where F: FnOnce(&CStr) -> Result<R>
     {
-        CString::new(self)?.with_cstr(f)
+        run_with_cstr(self.as_bytes(), f)
     }
 }
 
@@ -168,6 +169,58 @@ impl WithCStr for String
     fn with_cstr<F, R>(self, f: F) -> Result<R>
         where F: FnOnce(&CStr) -> Result<R>
     {
-        CString::new(self)?.with_cstr(f)
+        run_with_cstr(self.as_bytes(), f)
     }
 }
+
+/// Size of the stack buffer [`run_with_cstr`] uses to avoid the heap.
+///
+/// The NUL terminator must fit alongside the string, so the longest
+/// string handled on the stack is one byte shorter than this.
+const MAX_STACK_ALLOCATION: usize = 256;
+
+/// Call `f` with a NUL-terminated copy of `bytes`.
+///
+/// Modeled on std's internal `run_with_cstr`: short strings are copied into
+/// a stack buffer with the terminating NUL appended, and only strings that
+/// do not fit fall back to a heap allocation. Interior NULs are rejected
+/// before `f` is called.
+fn run_with_cstr<F, R>(bytes: &[u8], f: F) -> Result<R>
+    where F: FnOnce(&CStr) -> Result<R>
+{
+    if bytes.len() >= MAX_STACK_ALLOCATION {
+        return run_with_cstr_allocating(bytes, f);
+    }
+
+    let mut buf = MaybeUninit::<[u8; MAX_STACK_ALLOCATION]>::uninit();
+    let buf_ptr = buf.as_mut_ptr() as *mut u8;
+
+    // SAFETY: bytes.len() < MAX_STACK_ALLOCATION, so the copy and the
+    //         trailing NUL both stay within the stack buffer.
+    let with_nul = unsafe {
+        copy_nonoverlapping(bytes.as_ptr(), buf_ptr, bytes.len());
+        buf_ptr.add(bytes.len()).write(0);
+        slice::from_raw_parts(buf_ptr, bytes.len() + 1)
+    };
+
+    match CStr::from_bytes_with_nul(with_nul) {
+        Ok(cstr) => f(cstr),
+        Err(_)   => Err(interior_nul_error()),
+    }
+}
+
+/// Heap fallback of [`run_with_cstr`] for strings that do not fit the stack.
+fn run_with_cstr_allocating<F, R>(bytes: &[u8], f: F) -> Result<R>
+    where F: FnOnce(&CStr) -> Result<R>
+{
+    match CString::new(bytes) {
+        Ok(cstr) => f(&cstr),
+        Err(_)   => Err(interior_nul_error()),
+    }
+}
+
+/// Error returned when a string contains an interior NUL byte.
+fn interior_nul_error() -> Error
+{
+    Error::new(ErrorKind::InvalidInput, "data provided contains an interior nul byte")
+}
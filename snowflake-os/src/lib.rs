@@ -60,6 +60,7 @@ pub use libc::{
     POLLIN,
     SIGCHLD,
     SIGKILL,
+    SIGTERM,
     gid_t,
     mode_t,
     pid_t,
@@ -119,6 +120,19 @@ pub fn execve(
     result.into_err()
 }
 
+/// fork(2).
+///
+/// Returns `0` in the child process and the child's pid in the parent.
+pub fn fork() -> Result<pid_t>
+{
+    unsafe {
+        match libc::fork() {
+            -1  => Err(Error::last_os_error()),
+            pid => Ok(pid),
+        }
+    }
+}
+
 /// getgid(2).
 pub fn getgid() -> gid_t
 {
@@ -211,6 +225,25 @@ pub fn open(
     })
 }
 
+/// openat(2).
+pub fn openat(
+    dirfd:     &impl AsRawFd,
+    pathname:  impl WithCStr,
+    mut flags: libc::c_int,
+    mode:      mode_t,
+) -> Result<OwnedFd>
+{
+    flags |= libc::O_CLOEXEC;
+    pathname.with_cstr(|pathname| {
+        unsafe {
+            match libc::openat(dirfd.as_raw_fd(), pathname.as_ptr(), flags, mode) {
+                -1 => Err(Error::last_os_error()),
+                fd => Ok(OwnedFd::from_raw_fd(fd)),
+            }
+        }
+    })
+}
+
 /// pipe2(2).
 pub fn pipe2(mut flags: libc::c_int) -> Result<[OwnedFd; 2]>
 {
@@ -235,6 +268,23 @@ pub fn poll(fds: &mut [pollfd], timeout: libc::c_int) -> Result<usize>
     }
 }
 
+/// read(2).
+pub fn read(fd: &impl AsRawFd, buf: &mut [u8]) -> Result<usize>
+{
+    unsafe {
+        match
+            libc::read(
+                fd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        {
+            -1 => Err(Error::last_os_error()),
+            n  => Ok(n as usize),
+        }
+    }
+}
+
 /// readlink(2).
 pub fn readlink<'a>(
     pathname: impl WithCStr,
@@ -311,6 +361,41 @@ pub fn symlinkat(
     }))
 }
 
+/// wait4(2).
+pub fn wait4(pid: pid_t, options: libc::c_int)
+    -> Result<(pid_t, ExitStatus, libc::rusage)>
+{
+    unsafe {
+        let mut wstatus = 0;
+        let mut rusage = MaybeUninit::<libc::rusage>::zeroed();
+        match libc::wait4(pid, &mut wstatus, options, rusage.as_mut_ptr()) {
+            -1  => Err(Error::last_os_error()),
+            pid => Ok((
+                pid,
+                ExitStatus::from_raw(wstatus),
+                rusage.assume_init(),
+            )),
+        }
+    }
+}
+
+/// write(2).
+pub fn write(fd: &impl AsRawFd, buf: &[u8]) -> Result<usize>
+{
+    unsafe {
+        match
+            libc::write(
+                fd.as_raw_fd(),
+                buf.as_ptr() as *const libc::c_void,
+                buf.len(),
+            )
+        {
+            -1 => Err(Error::last_os_error()),
+            n  => Ok(n as usize),
+        }
+    }
+}
+
 /// waitpid(2).
 pub fn waitpid(pid: pid_t, options: libc::c_int)
     -> Result<(pid_t, ExitStatus)>
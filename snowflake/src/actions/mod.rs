@@ -10,4 +10,8 @@ pub struct ActionContext
 {
     pub scratch_dir: RawFd,
     pub log_file: RawFd,
+
+    /// Jobserver pipe fds `(read, write)` shared by the scheduler across all
+    /// concurrent actions, or `None` to let each tool pick its own parallelism.
+    pub jobserver: Option<(RawFd, RawFd)>,
 }
@@ -3,7 +3,8 @@
 use {
     crate::{
         config::{BASH_PATH, COREUTILS_PATH},
-        container::{Command, Mount, RunError, Stdio},
+        container::{Capabilities, Command, Mount, Namespaces, RunError, Stdio},
+        jobserver,
     },
     super::ActionContext,
     snowflake_os::{self as os, cstr, cstr::CStringArray},
@@ -79,9 +80,23 @@ fn run_command(
     info:    PerformRunAction,
 ) -> Result<(), RunActionError>
 {
+    let mut info = info;
+
+    // Advertise the shared jobserver to cooperating build tools so that
+    // nested builds draw from one global concurrency budget.
+    if let Some((r, w)) = context.jobserver {
+        info.environment.push(
+            CString::new(format!("MAKEFLAGS={}", jobserver::makeflags(r, w)))
+                .expect("MAKEFLAGS contains no NUL"),
+        );
+    }
+
     // Configure the command to run.
     let command = Command{
 
+        // Fully isolate the action in its own namespaces.
+        namespaces: Namespaces::ALL,
+
         // Map root inside container to actual user outside container.
         setgroups: "deny\n".into(),
         uid_map:   format!("0 {} 1\n", os::getuid()).into(),
@@ -103,10 +118,28 @@ fn run_command(
         execve_argv:     info.arguments,
         execve_envp:     info.environment,
 
-        // Open the log file and redirect stdio.
+        // Reap orphaned grandchildren spawned by the build tool.
+        init: true,
+
+        // No cgroup limits are applied to this action by default.
+        cgroup: None,
+
+        // Share the scheduler's jobserver budget, if one was provided.
+        jobserver: context.jobserver,
+
+        // stderr is piped (below) and forwarded to this log file line by
+        // line as the action runs, so the log is live rather than a dump.
+        log_file: Some(context.log_file),
+
+        // Harden the sandbox: no regained privileges, no capabilities.
+        no_new_privs:        true,
+        retain_capabilities: Capabilities::NONE,
+
+        // Close stdin; send stdout and stderr to the log file. stderr is piped so it can
+        // be forwarded to the log file incrementally while the action runs.
         stdin:  Stdio::Close,
         stdout: Stdio::Dup2{oldfd: context.log_file},
-        stderr: Stdio::Dup2{oldfd: context.log_file},
+        stderr: Stdio::MakePipe,
 
     };
 
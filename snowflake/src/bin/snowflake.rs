@@ -3,7 +3,7 @@
 #![feature(duration_constants)]
 
 use {
-    snowflake::container::{Command, Mount, Stdio},
+    snowflake::container::{Capabilities, Command, Mount, Namespaces, Stdio},
     snowflake_os as os,
     std::{ffi::CString, io::Result, os::unix::io::AsRawFd, time::Duration},
 };
@@ -18,6 +18,8 @@ fn main() -> Result<()>
 
     let command = Command{
 
+        namespaces: Namespaces::ALL,
+
         setgroups: b"deny\0".to_vec(),
         uid_map:   format!("0 {} 1\n", os::getuid()).into(),
         gid_map:   format!("0 {} 1\n", os::getgid()).into(),
@@ -66,6 +68,13 @@ fn main() -> Result<()>
         ]),
         execve_envp: os::cstr::CStringArray::new(),
 
+        init: false,
+        cgroup: None,
+        jobserver: None,
+        log_file: None,
+        no_new_privs: false,
+        retain_capabilities: Capabilities::ALL,
+
         stdin: Stdio::Close,
         stdout: Stdio::Inherit,
         stderr: Stdio::Inherit,
@@ -0,0 +1,148 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Resource limits and whole-subtree kill via cgroup v2.
+
+use {
+    super::{Error, error::ResultExt},
+    snowflake_os as os,
+    std::os::unix::io::{AsRawFd, OwnedFd, RawFd},
+};
+
+/// Resource limits to apply to a container's cgroup.
+///
+/// Each field corresponds to a cgroup v2 interface file; `None` leaves the
+/// limit unset, inheriting the parent cgroup's value.
+#[derive(Default)]
+#[allow(missing_docs)]
+pub struct CgroupSpec
+{
+    /// Written to `memory.max`, as a byte count.
+    pub memory_max: Option<u64>,
+
+    /// Written to `pids.max`, as a process count.
+    pub pids_max: Option<u64>,
+
+    /// Written to `cpu.max` verbatim, e.g. `"100000 100000"`.
+    pub cpu_max: Option<String>,
+}
+
+/// Accounting read back from a cgroup after a run.
+#[allow(missing_docs)]
+pub struct CgroupStats
+{
+    /// Whether the OOM killer fired inside the cgroup.
+    pub oom_killed: bool,
+
+    /// Peak memory usage, in bytes, as reported by `memory.peak`.
+    pub peak_memory: u64,
+}
+
+/// A delegated cgroup v2 directory holding a container's processes.
+pub struct Cgroup
+{
+    /// `O_DIRECTORY` handle to the cgroup directory.
+    dir: OwnedFd,
+}
+
+impl Cgroup
+{
+    /// Create a child cgroup under `parent` and apply `spec`.
+    ///
+    /// `parent` is an `O_DIRECTORY` fd to an existing (delegated) cgroup v2
+    /// directory, and `name` is the basename of the child to create.
+    pub fn create(
+        parent: &impl AsRawFd,
+        name:   &str,
+        spec:   &CgroupSpec,
+    ) -> Result<Self, Error>
+    {
+        os::mkdirat(parent, name, 0o755).context("mkdirat: cgroup")?;
+
+        let dir = os::openat(parent, name, os::O_DIRECTORY, 0)
+            .context("openat: cgroup")?;
+        let cgroup = Self{dir};
+
+        if let Some(memory_max) = spec.memory_max {
+            cgroup.write_file("memory.max", format!("{}\n", memory_max).as_bytes())
+                .context("memory.max")?;
+        }
+        if let Some(pids_max) = spec.pids_max {
+            cgroup.write_file("pids.max", format!("{}\n", pids_max).as_bytes())
+                .context("pids.max")?;
+        }
+        if let Some(cpu_max) = &spec.cpu_max {
+            cgroup.write_file("cpu.max", format!("{}\n", cpu_max).as_bytes())
+                .context("cpu.max")?;
+        }
+
+        Ok(cgroup)
+    }
+
+    /// Atomically SIGKILL every process in the cgroup subtree.
+    ///
+    /// Unlike killing a lone pid, this reaches processes that escaped into
+    /// other process groups by writing `"1"` to `cgroup.kill`.
+    pub fn kill(&self) -> Result<(), Error>
+    {
+        self.write_file("cgroup.kill", b"1\n").context("cgroup.kill")
+    }
+
+    /// Read back memory accounting after the container has exited.
+    pub fn stats(&self) -> Result<CgroupStats, Error>
+    {
+        let events = self.read_file("memory.events").context("memory.events")?;
+        let oom_killed = event_count(&events, "oom_kill") > 0;
+
+        let peak = self.read_file("memory.peak").context("memory.peak")?;
+        let peak_memory = peak.trim().parse().unwrap_or(0);
+
+        Ok(CgroupStats{oom_killed, peak_memory})
+    }
+
+    /// Write a single interface file relative to the cgroup directory.
+    fn write_file(&self, name: &str, data: &[u8]) -> Result<(), std::io::Error>
+    {
+        let file = os::openat(&self.dir, name, os::O_TRUNC | os::O_WRONLY, 0)?;
+        let nwritten = os::write(&file, data)?;
+        if nwritten != data.len() {
+            return Err(std::io::Error::from_raw_os_error(os::EAGAIN));
+        }
+        Ok(())
+    }
+
+    /// Read a single interface file relative to the cgroup directory.
+    fn read_file(&self, name: &str) -> Result<String, std::io::Error>
+    {
+        let file = os::openat(&self.dir, name, 0, 0)?;
+        let mut contents = String::new();
+        let mut buf = [0; 512];
+        loop {
+            match os::read(&file, &mut buf)? {
+                0 => break,
+                n => contents.push_str(&String::from_utf8_lossy(&buf[.. n])),
+            }
+        }
+        Ok(contents)
+    }
+}
+
+impl AsRawFd for Cgroup
+{
+    fn as_raw_fd(&self) -> RawFd
+    {
+        self.dir.as_raw_fd()
+    }
+}
+
+/// Look up a counter in the `key value` lines of `memory.events`.
+fn event_count(events: &str, key: &str) -> u64
+{
+    for line in events.lines() {
+        if let Some((name, value)) = line.split_once(' ') {
+            if name == key {
+                return value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    0
+}
@@ -21,6 +21,11 @@ impl Drop for KillGuard
 {
     fn drop(&mut self)
     {
+        // Send SIGTERM first so an interposed init process (see
+        // spawn::become_init) can catch it and forward it to the main child,
+        // then SIGKILL at once, without waiting, to guarantee teardown of the
+        // whole PID namespace regardless of what caught the first signal.
+        let _ = os::kill(self.0, os::SIGTERM);
         let _ = os::kill(self.0, os::SIGKILL);
         let _ = os::waitpid(self.0, 0);
     }
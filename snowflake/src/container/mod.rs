@@ -4,15 +4,20 @@
 
 use {
     snowflake_os::cstr::CStringArray,
-    std::{ffi::CString, os::unix::io::RawFd},
+    std::{ffi::CString, fs::File, os::unix::io::RawFd},
 };
 
-pub use {self::{error::Error, run::*}, std::process::ExitStatus};
+pub use {
+    self::{cgroup::*, error::Error, run::*},
+    std::process::ExitStatus,
+};
 
+mod cgroup;
 mod error;
 mod kill_guard;
 mod run;
 mod spawn;
+mod stderr_forwarder;
 
 /// Command to be run in a container.
 #[allow(missing_docs)]
@@ -27,6 +32,9 @@ pub struct Command
     // Their use must not require any heap allocations.
     // So we use CString instead of OsString, etc.
 
+    /// Namespaces to unshare for the container.
+    pub namespaces: Namespaces,
+
     /// Contents of `/proc/self/{setgroups,{u,g}id_map}`.
     pub setgroups: Vec<u8>,
     pub uid_map:   Vec<u8>,
@@ -49,6 +57,41 @@ pub struct Command
     pub execve_argv:     CStringArray,
     pub execve_envp:     CStringArray,
 
+    /// Interpose a minimal init process as PID 1 of the container.
+    ///
+    /// Ordinary build tools are not written to act as init: orphaned
+    /// grandchildren reparented to PID 1 would never be reaped. When this
+    /// is set, the namespace's PID 1 forks the real command and reaps every
+    /// child in a loop, mirroring the main child's exit status on the way out.
+    pub init: bool,
+
+    /// Cgroup v2 subtree enforcing the container's resource limits.
+    ///
+    /// When set, the container is placed into this cgroup, a timeout kills
+    /// the whole subtree rather than a lone pid, and memory accounting is
+    /// read back after the run.
+    pub cgroup: Option<Cgroup>,
+
+    /// Jobserver pipe fds `(read, write)` to keep open across `execve`.
+    ///
+    /// These are advertised to the command via `MAKEFLAGS` so that nested
+    /// build tools share a global concurrency budget. `FD_CLOEXEC` is
+    /// cleared on them just before `execve` so the child inherits them.
+    pub jobserver: Option<(RawFd, RawFd)>,
+
+    /// Log file to stream the child's stderr to while it runs.
+    ///
+    /// Only used when [`stderr`](Self::stderr) is [`Stdio::MakePipe`]: as the
+    /// child writes, complete lines are forwarded to this fd instead of being
+    /// dumped only at teardown.
+    pub log_file: Option<RawFd>,
+
+    /// Set `PR_SET_NO_NEW_PRIVS` so the command cannot regain privileges.
+    pub no_new_privs: bool,
+
+    /// Capabilities to retain; all others are dropped before `execve`.
+    pub retain_capabilities: Capabilities,
+
     /// File descriptors to adjust.
     pub stdin:  Stdio,
     pub stdout: Stdio,
@@ -66,6 +109,81 @@ pub struct Mount
     pub data:           CString,
 }
 
+/// Set of namespaces to unshare, as a mask of `CLONE_NEW*` flags.
+///
+/// The default ([`Namespaces::ALL`]) isolates the container fully. Callers
+/// can drop flags to, for example, keep the host network namespace for
+/// actions that must fetch dependencies.
+#[derive(Clone, Copy)]
+pub struct Namespaces(pub libc::c_int);
+
+impl Namespaces
+{
+    /// New cgroup namespace.
+    pub const CGROUP: Self = Self(libc::CLONE_NEWCGROUP);
+
+    /// New IPC namespace.
+    pub const IPC: Self = Self(libc::CLONE_NEWIPC);
+
+    /// New network namespace.
+    pub const NET: Self = Self(libc::CLONE_NEWNET);
+
+    /// New mount namespace.
+    pub const MOUNT: Self = Self(libc::CLONE_NEWNS);
+
+    /// New PID namespace.
+    pub const PID: Self = Self(libc::CLONE_NEWPID);
+
+    /// New user namespace.
+    pub const USER: Self = Self(libc::CLONE_NEWUSER);
+
+    /// New UTS namespace.
+    pub const UTS: Self = Self(libc::CLONE_NEWUTS);
+
+    /// Every namespace: a fully-isolated container.
+    pub const ALL: Self = Self(
+        libc::CLONE_NEWCGROUP | libc::CLONE_NEWIPC | libc::CLONE_NEWNET |
+        libc::CLONE_NEWNS     | libc::CLONE_NEWPID | libc::CLONE_NEWUSER |
+        libc::CLONE_NEWUTS
+    );
+
+    /// Whether every namespace in `other` is present in `self`.
+    pub fn contains(self, other: Self) -> bool
+    {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Namespaces
+{
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self
+    {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Set of Linux capabilities, as a bitmask indexed by capability number.
+///
+/// Bit `n` corresponds to the capability whose number is `n` (e.g. bit
+/// `CAP_SYS_ADMIN`). This is the representation used by `capset(2)`.
+#[derive(Clone, Copy, Default)]
+pub struct Capabilities(pub u64);
+
+impl Capabilities
+{
+    /// Retain no capabilities at all.
+    pub const NONE: Self = Self(0);
+
+    /// Retain every valid capability (bits `0 ..= CAP_LAST_CAP`).
+    ///
+    /// Only the defined capability bits are set; the 23 reserved high bits
+    /// are left clear, because `capset(2)` rejects a permitted/inheritable
+    /// set containing bits above `CAP_LAST_CAP` (40 on current kernels).
+    pub const ALL: Self = Self((1u64 << 41) - 1);
+}
+
 /// How to adjust a file descriptor.
 #[allow(missing_docs)]
 #[derive(Clone, Copy)]
@@ -79,4 +197,24 @@ pub enum Stdio
 
     /// Duplicate `oldfd` into the file descriptor.
     Dup2{oldfd: RawFd},
+
+    /// Connect the file descriptor to a freshly-created pipe.
+    ///
+    /// The parent-side end is returned from [`Command::spawn`] in a
+    /// [`StdioPipes`], so callers can stream data to or from the container.
+    MakePipe,
+}
+
+/// Parent-side ends of any pipes created by [`Stdio::MakePipe`].
+///
+/// Each field is `Some` exactly when the corresponding stream was configured
+/// with [`Stdio::MakePipe`]. `stdin` is the write end; `stdout` and `stderr`
+/// are read ends.
+#[derive(Default)]
+#[allow(missing_docs)]
+pub struct StdioPipes
+{
+    pub stdin:  Option<File>,
+    pub stdout: Option<File>,
+    pub stderr: Option<File>,
 }
@@ -1,17 +1,49 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 
 use {
-    super::{Command, Error, error::ResultExt, kill_guard::*},
+    super::{
+        Command, Error, error::ResultExt, kill_guard::*,
+        stderr_forwarder::{StderrForwarder, set_nonblocking},
+    },
     snowflake_os as os,
     std::{
         mem::forget,
         os::unix::io::AsRawFd,
         process::ExitStatus,
-        slice,
-        time::Duration,
+        time::{Duration, Instant},
     },
 };
 
+/// Resource usage and timing of a single container run.
+///
+/// The CPU, memory, and context-switch figures come from `wait4` on the
+/// container's top-level process. When [`Command::init`] is set (the default
+/// for run actions), that process is the interposed init reaper, so these
+/// figures are aggregated over the whole container subtree it reaped — the
+/// build tool *and* every descendant it spawned — rather than the build
+/// tool alone. `max_rss` is likewise the peak across all reaped processes.
+#[allow(missing_docs)]
+pub struct RunStats
+{
+    /// Wall-clock time the container took, for comparison with the timeout.
+    pub wall_time: Duration,
+
+    /// User-mode CPU time (`ru_utime`).
+    pub user_time: Duration,
+
+    /// Kernel-mode CPU time (`ru_stime`).
+    pub system_time: Duration,
+
+    /// Maximum resident set size in kibibytes (`ru_maxrss`).
+    pub max_rss: i64,
+
+    /// Voluntary context switches (`ru_nvcsw`).
+    pub voluntary_context_switches: i64,
+
+    /// Involuntary context switches (`ru_nivcsw`).
+    pub involuntary_context_switches: i64,
+}
+
 /// Error returned by [`Command::run`].
 #[allow(missing_docs)]
 #[derive(Debug, thiserror::Error)]
@@ -25,6 +57,9 @@ pub enum RunError
 
     #[error("Command terminated unsuccessfully: Status {0}")]
     Unsuccessful(ExitStatus),
+
+    #[error("Command was killed by the OOM killer (peak {0} bytes)")]
+    OutOfMemory(u64),
 }
 
 impl Command
@@ -32,39 +67,116 @@ impl Command
     /// Spawn the container and wait for it to terminate.
     ///
     /// If the container takes longer to run than the timeout, it is killed.
-    pub fn run(&self, timeout: Duration) -> Result<(), RunError>
+    pub fn run(&self, timeout: Duration) -> Result<RunStats, RunError>
     {
         // Spawn the child process.
-        let (pid, pidfd) = self.spawn()?;
+        let started = Instant::now();
+        // The container is placed into its cgroup atomically at clone3(2)
+        // time via CLONE_INTO_CGROUP, so no post-spawn migration is needed.
+        let (pid, pidfd, pipes) = self.spawn()?;
         let kill_guard = KillGuard::new(pid);
 
-        // Once the pidfd is readable, the child process has terminated.
-        let mut pollfd = os::pollfd{
-            fd:      pidfd.as_raw_fd(),
-            events:  os::POLLIN,
-            revents: 0,
+        // While the container runs, forward any piped stderr to the log file
+        // line by line so long-running actions produce live logs instead of
+        // one dump at teardown.
+        let mut forwarder = match (&pipes.stderr, self.log_file) {
+            (Some(stderr), Some(log_file)) => {
+                set_nonblocking(stderr.as_raw_fd())
+                    .context("stderr: O_NONBLOCK")?;
+                Some(StderrForwarder::new(stderr.as_raw_fd(), log_file))
+            },
+            _ => None,
+        };
+
+        // Wait until the pidfd becomes readable, which means the child has
+        // terminated, draining stderr to the log meanwhile. A timeout fires
+        // if the deadline passes before the child exits.
+        let deadline = started + timeout;
+        let timed_out = loop {
+            let now = Instant::now();
+            let remaining = deadline.saturating_duration_since(now);
+            if remaining.is_zero() {
+                break true;
+            }
+
+            let stderr_fd = forwarder.as_ref().map_or(-1, StderrForwarder::fd);
+            let mut fds = [
+                os::pollfd{fd: pidfd.as_raw_fd(), events: os::POLLIN, revents: 0},
+                os::pollfd{fd: stderr_fd,         events: os::POLLIN, revents: 0},
+            ];
+            let npolled = os::poll(
+                &mut fds,
+                remaining.as_millis().try_into().unwrap_or(i32::MAX),
+            ).context("poll")?;
+
+            if npolled == 0 {
+                break true;
+            }
+
+            // Forward stderr first so lines reach the log promptly.
+            if let Some(forwarder) = &mut forwarder {
+                if fds[1].revents != 0 {
+                    forwarder.forward_available();
+                }
+            }
+
+            // Then check whether the child has terminated.
+            if fds[0].revents != 0 {
+                break false;
+            }
         };
-        let npolled = os::poll(
-            slice::from_mut(&mut pollfd),
-            timeout.as_millis().try_into().unwrap_or(i32::MAX),
-        ).context("poll")?;
 
-        // If poll(2) returned 0, there was a timeout.
-        if npolled == 0 {
+        // If the deadline passed before the child exited, there was a timeout.
+        if timed_out {
+            // Kill the whole subtree, not just the lone pid, so that
+            // processes that escaped into other groups are cleaned up too.
+            if let Some(cgroup) = &self.cgroup {
+                let _ = cgroup.kill();
+            }
             return Err(RunError::Timeout(timeout));
         }
 
-        // Reap the child process and find its wait status.
-        let (_, wstatus) = os::waitpid(pid, 0).context("waitpid")?;
+        // Reap the child process and collect its resource usage.
+        let (_, wstatus, rusage) = os::wait4(pid, 0).context("wait4")?;
+        let wall_time = started.elapsed();
 
         // No more need to kill and reap.
         forget(kill_guard);
 
+        // Flush any stderr still in the pipe, including a trailing partial
+        // line, now that the child has exited.
+        if let Some(forwarder) = &mut forwarder {
+            forwarder.forward_available();
+            forwarder.drain();
+        }
+
+        // Report whether the OOM killer fired before anything else, since
+        // an OOM kill usually surfaces as a plain unsuccessful status.
+        if let Some(cgroup) = &self.cgroup {
+            let stats = cgroup.stats()?;
+            if stats.oom_killed {
+                return Err(RunError::OutOfMemory(stats.peak_memory));
+            }
+        }
+
         // Check the wait status of the child process.
         if !wstatus.success() {
             return Err(RunError::Unsuccessful(wstatus));
         }
 
-        Ok(())
+        Ok(RunStats{
+            wall_time,
+            user_time:                   timeval_to_duration(rusage.ru_utime),
+            system_time:                 timeval_to_duration(rusage.ru_stime),
+            max_rss:                     rusage.ru_maxrss as i64,
+            voluntary_context_switches:  rusage.ru_nvcsw as i64,
+            involuntary_context_switches: rusage.ru_nivcsw as i64,
+        })
     }
 }
+
+/// Convert a [`libc::timeval`] to a [`Duration`].
+fn timeval_to_duration(tv: libc::timeval) -> Duration
+{
+    Duration::new(tv.tv_sec as u64, (tv.tv_usec as u32) * 1000)
+}
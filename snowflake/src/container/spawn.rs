@@ -1,22 +1,37 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 
 use {
-    super::{Command, Error, Stdio, error::ResultExt, kill_guard::*},
+    super::{
+        Capabilities, Command, Error, Namespaces, Stdio, StdioPipes,
+        error::ResultExt, kill_guard::*,
+        stderr_forwarder::{StderrForwarder, set_nonblocking},
+    },
     snowflake_os as os,
     std::{
         ffi::{CStr, CString},
         fs::File,
-        io::{self, Read, Write},
+        io::{self, Write},
         mem::{MaybeUninit, forget, size_of_val, zeroed},
-        os::{linux::process::PidFd, unix::io::{FromRawFd, RawFd}},
+        os::{
+            linux::process::PidFd,
+            unix::{io::{AsRawFd, FromRawFd, OwnedFd, RawFd}, process::ExitStatusExt},
+        },
         panic::always_abort,
+        process::ExitStatus,
+        sync::atomic::{AtomicI32, Ordering},
     },
 };
 
+/// Footer marking the end of a complete pre-execve error packet.
+const ERROR_PACKET_FOOTER: [u8; 4] = *b"SNOW";
+
+/// `CLONE_INTO_CGROUP` from `<linux/sched.h>`; not exposed by libc.
+const CLONE_INTO_CGROUP: u64 = 0x2_0000_0000;
+
 impl Command
 {
     /// Spawn the container.
-    pub fn spawn(&self) -> Result<(os::pid_t, PidFd), Error>
+    pub fn spawn(&self) -> Result<(os::pid_t, PidFd, StdioPipes), Error>
     {
         // For unknown reasons, using fchdir(2) in the child process
         // prevents mount(2) and chroot(2) from working with relative paths.
@@ -34,17 +49,31 @@ impl Command
         let mut pipe_r = File::from(pipe_r);
         let mut pipe_w = File::from(pipe_w);
 
-        // Prepare the call to clone3(2).
-        let clone3_flags = {
-            os::CLONE_NEWCGROUP |  // New cgroup namespace.
-            os::CLONE_NEWIPC    |  // New IPC namespace.
-            os::CLONE_NEWNET    |  // New network namespace.
-            os::CLONE_NEWNS     |  // New mount namespace.
-            os::CLONE_NEWPID    |  // New PID namespace.
-            os::CLONE_NEWUSER   |  // New user namespace.
-            os::CLONE_NEWUTS    |  // New UTS namespace.
-            os::CLONE_PIDFD        // Create new pidfd.
-        };
+        // Create any requested stdio pipes. The child-side ends are dup'd
+        // onto fd 0/1/2 by `adjust_fd`; the parent-side ends are returned.
+        // The child-side `OwnedFd`s are kept alive until after clone3(2),
+        // then dropped in the parent so only the child keeps them.
+        let mut parent_pipes = StdioPipes::default();
+        let mut child_ends: [Option<OwnedFd>; 3] = [None, None, None];
+        let stdin  = Self::make_stdio(self.stdin,  true,
+                         &mut parent_pipes.stdin,  &mut child_ends[0])?;
+        let stdout = Self::make_stdio(self.stdout, false,
+                         &mut parent_pipes.stdout, &mut child_ends[1])?;
+        let stderr = Self::make_stdio(self.stderr, false,
+                         &mut parent_pipes.stderr, &mut child_ends[2])?;
+
+        // Prepare the call to clone3(2). The namespaces to unshare are
+        // taken from the command; a pidfd is always created.
+        let mut clone3_flags =
+            (self.namespaces.0 | os::CLONE_PIDFD) as u64;
+
+        // If a cgroup was supplied, start the child already inside it rather
+        // than migrating it afterwards, closing the window where it would run
+        // briefly outside its resource limits.
+        let cgroup_fd = self.cgroup.as_ref().map(AsRawFd::as_raw_fd);
+        if cgroup_fd.is_some() {
+            clone3_flags |= CLONE_INTO_CGROUP;
+        }
 
         // clone3(2) will store the pidfd in here.
         let mut pidfd: RawFd = -1;
@@ -68,9 +97,12 @@ impl Command
                 cgroup:       u64,
             }
             let mut cl_args = zeroed::<clone_args>();
-            cl_args.flags       = clone3_flags as u64;
+            cl_args.flags       = clone3_flags;
             cl_args.pidfd       = &mut pidfd as *mut RawFd as u64;
             cl_args.exit_signal = os::SIGCHLD as u64;
+            // The full clone_args size is passed below, so the kernel reads
+            // this `cgroup` field whenever CLONE_INTO_CGROUP is set.
+            cl_args.cgroup      = cgroup_fd.unwrap_or(0) as u64;
             libc::syscall(libc::SYS_clone3, &cl_args, size_of_val(&cl_args))
         };
 
@@ -85,14 +117,21 @@ impl Command
             // Make sure panics don't bubble up the stack.
             always_abort();
 
-            // Only returns if something went wrong.
-            let error = self.child_pre_execve(pipe_r, fchdir);
+            // Only returns if something went wrong. The error pipe's write
+            // end is passed so the init reaper can close its copy before
+            // entering the reap loop (see become_init).
+            let error = self.child_pre_execve(pipe_r, pipe_w.as_raw_fd(),
+                                              fchdir, [stdin, stdout, stderr]);
             let error = error.into_err();
 
-            // If an error occurred, send it to the parent process.
+            // If an error occurred, send it to the parent process as a
+            // framed packet: big-endian errno, the context string, and a
+            // fixed footer so the parent can tell a complete packet apart
+            // from a partial write.
             let errno = error.inner.raw_os_error().unwrap_or(-1);
-            let _ = pipe_w.write(&errno.to_ne_bytes());
+            let _ = pipe_w.write(&errno.to_be_bytes());
             let _ = pipe_w.write(error.context.as_bytes());
+            let _ = pipe_w.write(&ERROR_PACKET_FOOTER);
             os::_exit(1);
 
         }
@@ -106,6 +145,9 @@ impl Command
         // Close the write end of the pipe.
         drop(pipe_w);
 
+        // Close the child-side pipe ends so only the child holds them.
+        drop(child_ends);
+
         // SAFETY: This is definitely a pidfd.
         let pidfd = unsafe { PidFd::from_raw_fd(pidfd) };
 
@@ -113,69 +155,160 @@ impl Command
         // Once it does, pipe_w will be closed due to CLOEXEC,
         // which in turn causes this read to complete at EOF.
         // If this read completes with data, an error was sent.
-        let mut buf = Vec::new();
-        match pipe_r.read_to_end(&mut buf).context("read: pipe_r")? {
-            0 => {
-                forget(kill_guard);  // Keep it running!
-                Ok((pid, pidfd))
-            },
-            n if n > 4 => {
-                let errno = [buf[0], buf[1], buf[2], buf[3]];
-                let errno = i32::from_ne_bytes(errno);
-                let context = String::from_utf8_lossy(&buf[4 ..]);
-                let context = context.into_owned();  // Can't borrow buf.
-                Err(Error::from_raw_os_error(errno, context))
-            },
-            _ => {
-                // Unlikely scenario where the child process
-                // couldn't write the entire error packet.
-                Err(Error::other("Unknown error", "child_pre_execve"))
+        //
+        // While waiting we also forward any piped stderr to the log file, so
+        // a chatty child can't deadlock by filling its stderr pipe while we
+        // block on the error pipe, and so long-running actions log live.
+        let buf = self.wait_for_execve(&mut pipe_r, &parent_pipes)?;
+
+        // EOF with no bytes means execve(2) succeeded and the pipe closed.
+        if buf.is_empty() {
+            forget(kill_guard);  // Keep it running!
+            return Ok((pid, pidfd, parent_pipes));
+        }
+
+        // A complete packet is at least the errno and the footer, and ends
+        // with the footer. Anything shorter is a half-written message.
+        let footer_len = ERROR_PACKET_FOOTER.len();
+        if buf.len() < 4 + footer_len
+            || buf[buf.len() - footer_len ..] != ERROR_PACKET_FOOTER
+        {
+            return Err(Error::other(
+                "Truncated error packet",
+                "child_pre_execve",
+            ));
+        }
+
+        let body = buf.len() - footer_len;
+        let errno = i32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let context = String::from_utf8_lossy(&buf[4 .. body]).into_owned();
+        Err(Error::from_raw_os_error(errno, context))
+    }
+
+    /// Read the child's error packet to EOF, forwarding stderr meanwhile.
+    ///
+    /// Both the error pipe and (when stderr is piped with a log file set) the
+    /// stderr pipe are made non-blocking and polled together, so stderr is
+    /// drained to the log file as it arrives rather than only at teardown.
+    fn wait_for_execve(&self, pipe_r: &mut File, pipes: &StdioPipes)
+        -> Result<Vec<u8>, Error>
+    {
+        let err_fd = pipe_r.as_raw_fd();
+        set_nonblocking(err_fd).context("pipe_r: O_NONBLOCK")?;
+
+        let mut forwarder = match (&pipes.stderr, self.log_file) {
+            (Some(stderr), Some(log_file)) => {
+                let fd = stderr.as_raw_fd();
+                set_nonblocking(fd).context("stderr: O_NONBLOCK")?;
+                Some(StderrForwarder::new(fd, log_file))
             },
+            _ => None,
+        };
+
+        let mut buf = Vec::new();
+        loop {
+            let stderr_fd = forwarder.as_ref().map_or(-1, StderrForwarder::fd);
+            let mut fds = [
+                os::pollfd{fd: err_fd,    events: os::POLLIN, revents: 0},
+                os::pollfd{fd: stderr_fd, events: os::POLLIN, revents: 0},
+            ];
+            os::poll(&mut fds, -1).context("poll: pipe_r")?;
+
+            // Forward stderr first so lines reach the log promptly.
+            if let Some(forwarder) = &mut forwarder {
+                if fds[1].revents != 0 {
+                    forwarder.forward_available();
+                }
+            }
+
+            // Then drain the error pipe; EOF there means execve happened.
+            if fds[0].revents != 0 && read_available(pipe_r, &mut buf)? {
+                break;
+            }
         }
+
+        // Flush any trailing stderr before returning.
+        if let Some(forwarder) = &mut forwarder {
+            forwarder.forward_available();
+            forwarder.drain();
+        }
+
+        Ok(buf)
     }
 
     /// The code that runs in the child process.
     ///
     /// Everything in here must be async-signal-safe!
     /// That implies no allocations and no panics may occur.
-    fn child_pre_execve(&self, pipe_r: File, fchdir: CString)
-        -> Result<!, Error>
+    fn child_pre_execve(
+        &self,
+        pipe_r:     File,
+        error_pipe: RawFd,
+        fchdir:     CString,
+        stdio:      [Stdio; 3],
+    ) -> Result<!, Error>
     {
         // Close the read end of the pipe.
         drop(pipe_r);
 
-        // Write to these files as requested.
-        Self::write_file(os::cstr!("/proc/self/setgroups"), &self.setgroups)
-            .context("/proc/self/setgroups")?;
-        Self::write_file(os::cstr!("/proc/self/uid_map"), &self.uid_map)
-            .context("/proc/self/uid_map")?;
-        Self::write_file(os::cstr!("/proc/self/gid_map"), &self.gid_map)
-            .context("/proc/self/gid_map")?;
+        // Set up the uid/gid maps, but only when we unshared the user
+        // namespace; without it these files are not ours to write.
+        if self.namespaces.contains(Namespaces::USER) {
+            Self::write_file(os::cstr!("/proc/self/setgroups"), &self.setgroups)
+                .context("/proc/self/setgroups")?;
+            Self::write_file(os::cstr!("/proc/self/uid_map"), &self.uid_map)
+                .context("/proc/self/uid_map")?;
+            Self::write_file(os::cstr!("/proc/self/gid_map"), &self.gid_map)
+                .context("/proc/self/gid_map")?;
+        }
 
         // Set working directory as requested.
         os::chdir(fchdir).context("fchdir")?;
 
-        // Perform each mount as requested.
-        for mount in &self.mounts {
-            os::mount(
-                &mount.source,
-                &mount.target,
-                &mount.filesystemtype,
-                mount.mountflags,
-                &mount.data,
-            ).context("mount")?;
-        }
+        // The mounts and chroot only make sense inside a private mount
+        // namespace, so skip them when one was not requested.
+        if self.namespaces.contains(Namespaces::MOUNT) {
+            // Perform each mount as requested.
+            for mount in &self.mounts {
+                os::mount(
+                    &mount.source,
+                    &mount.target,
+                    &mount.filesystemtype,
+                    mount.mountflags,
+                    &mount.data,
+                ).context("mount")?;
+            }
 
-        // Set root and working directories as requested.
-        os::chroot(&self.chroot).context("chroot")?;
-        os::chdir(&self.chroot_chdir).context("chroot_chdir")?;
+            // Set root and working directories as requested.
+            os::chroot(&self.chroot).context("chroot")?;
+            os::chdir(&self.chroot_chdir).context("chroot_chdir")?;
+        }
 
-        // Configure stdio as requested.
+        // Configure stdio as requested. `stdio` has already had any
+        // `MakePipe` streams resolved to the child-side pipe ends.
         // SAFETY: We will no longer use these file descriptors.
         unsafe {
-            Self::adjust_fd(0, self.stdin).context("stdin")?;
-            Self::adjust_fd(1, self.stdout).context("stdout")?;
-            Self::adjust_fd(2, self.stderr).context("stderr")?;
+            Self::adjust_fd(0, stdio[0]).context("stdin")?;
+            Self::adjust_fd(1, stdio[1]).context("stdout")?;
+            Self::adjust_fd(2, stdio[2]).context("stderr")?;
+        }
+
+        // Drop privileges now that the privileged setup (mounts, chroot)
+        // is done, so the command cannot regain them after execve.
+        self.restrict_capabilities().context("restrict_capabilities")?;
+
+        // Keep the jobserver pipe fds open across execve so child tools
+        // can cooperate on the shared concurrency budget via MAKEFLAGS.
+        if let Some((read, write)) = self.jobserver {
+            Self::clear_cloexec(read).context("jobserver: read")?;
+            Self::clear_cloexec(write).context("jobserver: write")?;
+        }
+
+        // Optionally interpose a minimal init process as PID 1.
+        // In the parent (PID 1) this never returns; in the child it
+        // returns and goes on to execve the actual command below.
+        if self.init {
+            Self::become_init(error_pipe)?;
         }
 
         // Replace the process with the requested program.
@@ -185,6 +318,31 @@ impl Command
         Err(Error{inner: error, context: "execve".into()})
     }
 
+    /// Fork a minimal init process to reap orphaned grandchildren.
+    ///
+    /// Returns in the child process, which should go on to `execve`.
+    /// The parent process becomes the reaper: it reaps every child in a
+    /// loop and, once the main child exits, mirrors its status.
+    ///
+    /// The reaper never `execve`s, so its copy of `error_pipe` (the
+    /// pre-execve error pipe's write end) would otherwise stay open for the
+    /// container's whole lifetime and keep the parent's `wait_for_execve`
+    /// read from ever reaching EOF — silently defeating the wall-clock
+    /// timeout. The reaper therefore closes it before reaping; the grandchild
+    /// still closes its own `CLOEXEC` copy at `execve`, so EOF fires exactly
+    /// when the protocol expects.
+    fn become_init(error_pipe: RawFd) -> Result<(), Error>
+    {
+        match os::fork().context("fork")? {
+            0   => Ok(()),                          // Child: carry on to execve.
+            pid => {                                // Parent: never returns.
+                // SAFETY: The reaper never uses this fd again.
+                unsafe { libc::close(error_pipe); }
+                reap_until_exit(pid)
+            },
+        }
+    }
+
     /// Write to a given file with a single write call.
     fn write_file(path: &CStr, data: &[u8]) -> Result<(), io::Error>
     {
@@ -197,6 +355,127 @@ impl Command
         Ok(())
     }
 
+    /// Set `no_new_privs` and drop capabilities down to the retained set.
+    ///
+    /// This must run after the privileged setup (mounts, chroot) but before
+    /// `execve`, and allocates nothing. After it returns, the command can no
+    /// longer regain any capability outside the retained mask.
+    fn restrict_capabilities(&self) -> Result<(), io::Error>
+    {
+        // Highest valid capability number on current kernels.
+        const CAP_LAST_CAP: u32 = 40;
+
+        // Header and payload of the capset(2) system call.
+        const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+        #[repr(C)]
+        struct CapHeader { version: u32, pid: libc::c_int }
+        #[repr(C)]
+        struct CapData { effective: u32, permitted: u32, inheritable: u32 }
+
+        let mask = self.retain_capabilities.0;
+
+        // Retaining every capability with no_new_privs off is exactly the
+        // pre-existing full-privilege path. Skip the capset(2)/bounding/
+        // ambient work so it stays a no-op instead of needlessly touching
+        // (and potentially failing on) sets that are already maximal.
+        if mask == Capabilities::ALL.0 && !self.no_new_privs {
+            return Ok(());
+        }
+
+        unsafe {
+            if self.no_new_privs
+                && libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == -1
+            {
+                return Err(io::Error::last_os_error());
+            }
+
+            // Drop from the bounding set every capability not retained.
+            for cap in 0 ..= CAP_LAST_CAP {
+                if mask & (1 << cap) == 0 {
+                    if libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+            }
+
+            // Clear the entire ambient set.
+            if libc::prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_CLEAR_ALL,
+                0, 0, 0,
+            ) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // Reduce the permitted, effective, and inheritable sets to the
+            // retained mask in one capset(2) call.
+            let header = CapHeader{
+                version: LINUX_CAPABILITY_VERSION_3,
+                pid:     0,
+            };
+            let data = [
+                CapData{
+                    effective:   mask as u32,
+                    permitted:   mask as u32,
+                    inheritable: mask as u32,
+                },
+                CapData{
+                    effective:   (mask >> 32) as u32,
+                    permitted:   (mask >> 32) as u32,
+                    inheritable: (mask >> 32) as u32,
+                },
+            ];
+            if libc::syscall(libc::SYS_capset, &header, data.as_ptr()) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resolve a [`Stdio`] that may be [`Stdio::MakePipe`] into a concrete one.
+    ///
+    /// For `MakePipe` a fresh `O_CLOEXEC` pipe is created: the parent-side end
+    /// is stored in `parent_end`, the child-side end is kept alive in
+    /// `child_end` (to be dup'd onto the target fd and closed in the parent),
+    /// and a [`Stdio::Dup2`] referring to the child-side end is returned.
+    fn make_stdio(
+        stdio:      Stdio,
+        is_input:   bool,
+        parent_end: &mut Option<File>,
+        child_end:  &mut Option<OwnedFd>,
+    ) -> Result<Stdio, Error>
+    {
+        match stdio {
+            Stdio::MakePipe => {
+                let [read, write] = os::pipe2(0).context("pipe2: stdio")?;
+                let (parent, child) =
+                    if is_input { (write, read) } else { (read, write) };
+                let childfd = child.as_raw_fd();
+                *parent_end = Some(File::from(parent));
+                *child_end  = Some(child);
+                Ok(Stdio::Dup2{oldfd: childfd})
+            },
+            other => Ok(other),
+        }
+    }
+
+    /// Clear the `FD_CLOEXEC` flag so `fd` survives `execve`.
+    fn clear_cloexec(fd: RawFd) -> Result<(), io::Error>
+    {
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            if flags == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            let flags = flags & !libc::FD_CLOEXEC;
+            if libc::fcntl(fd, libc::F_SETFD, flags) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
     /// Adjust a file descriptor.
     ///
     /// # Safety
@@ -221,6 +500,96 @@ impl Command
                     _  => Ok(()),
                 },
 
+            // Resolved to Dup2 by make_stdio before spawning.
+            Stdio::MakePipe =>
+                Ok(()),
+
+        }
+    }
+}
+
+/// Read whatever is available from `pipe_r` into `buf` without blocking.
+///
+/// Returns `true` once end of file is reached.
+fn read_available(pipe_r: &mut File, buf: &mut Vec<u8>) -> Result<bool, Error>
+{
+    let mut chunk = [0u8; 256];
+    loop {
+        match os::read(pipe_r, &mut chunk) {
+            Ok(0) => return Ok(true),
+            Ok(n) => buf.extend_from_slice(&chunk[.. n]),
+            Err(error) => {
+                if error.raw_os_error() == Some(os::EAGAIN) {
+                    return Ok(false);
+                }
+                return Err(error).context("read: pipe_r");
+            },
+        }
+    }
+}
+
+/// Pid of the main child, for the SIGTERM forwarder.
+static MAIN_CHILD_PID: AtomicI32 = AtomicI32::new(0);
+
+/// Forward a signal received by the init process to the main child.
+extern "C" fn forward_signal(sig: libc::c_int)
+{
+    let pid = MAIN_CHILD_PID.load(Ordering::Relaxed);
+    if pid > 0 {
+        unsafe { libc::kill(pid, sig); }
+    }
+}
+
+/// Reap children until there are none left, then mirror the main child.
+///
+/// This runs as PID 1 of the namespace. It reaps every child that exits,
+/// remembering the status of `main_child`. A `SIGTERM` from the outer
+/// [`KillGuard`] is forwarded to the main child; `SIGKILL` cannot be caught,
+/// but it tears down the whole PID namespace anyway.
+fn reap_until_exit(main_child: os::pid_t) -> !
+{
+    MAIN_CHILD_PID.store(main_child, Ordering::Relaxed);
+    unsafe {
+        libc::signal(libc::SIGTERM, forward_signal as libc::sighandler_t);
+    }
+
+    let mut main_status: Option<ExitStatus> = None;
+    loop {
+        match os::waitpid(-1, 0) {
+            Ok((pid, status)) => {
+                if pid == main_child {
+                    main_status = Some(status);
+                }
+            },
+            Err(error) => {
+                // ECHILD means every child has been reaped; anything else
+                // (such as EINTR) is retried by looping again.
+                if error.raw_os_error() != Some(libc::ECHILD) {
+                    continue;
+                }
+                match main_status {
+                    Some(status) => exit_mirroring(status),
+                    None         => os::_exit(1),
+                }
+            },
+        }
+    }
+}
+
+/// Terminate mirroring the exit status of the main child.
+///
+/// A normal exit re-exits with the same code; a signalled exit re-raises
+/// the terminating signal so the outer `ExitStatus` is faithful.
+fn exit_mirroring(status: ExitStatus) -> !
+{
+    if let Some(code) = status.code() {
+        os::_exit(code);
+    }
+    if let Some(sig) = status.signal() {
+        unsafe {
+            libc::signal(sig, libc::SIG_DFL);
+            libc::raise(sig);
         }
     }
+    os::_exit(1);
 }
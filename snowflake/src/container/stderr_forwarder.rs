@@ -0,0 +1,125 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Incremental forwarding of a child's stderr to a log file.
+
+use {
+    snowflake_os as os,
+    std::{io, os::unix::io::{BorrowedFd, RawFd}},
+};
+
+/// Forwards a piped stderr stream to a log file one line at a time.
+///
+/// Modeled on cc-rs's `StderrForwarder`: the stderr fd is read in
+/// non-blocking chunks while the container runs, complete lines are appended
+/// to the log file as they arrive, and any trailing partial line is buffered
+/// until the next chunk (or flushed by [`drain`](Self::drain) at exit).
+pub struct StderrForwarder
+{
+    /// Read end of the child's stderr pipe (non-blocking).
+    fd: RawFd,
+
+    /// Log file to append complete lines to.
+    log_file: RawFd,
+
+    /// Bytes read so far that do not yet end in a newline.
+    partial: Vec<u8>,
+}
+
+impl StderrForwarder
+{
+    /// Create a forwarder from the stderr read end to the log file.
+    pub fn new(fd: RawFd, log_file: RawFd) -> Self
+    {
+        Self{fd, log_file, partial: Vec::new()}
+    }
+
+    /// Read end of the stderr pipe, for use with `poll(2)`.
+    pub fn fd(&self) -> RawFd
+    {
+        self.fd
+    }
+
+    /// Forward everything currently available without blocking.
+    ///
+    /// Returns `true` once the stderr pipe has reached end of file, at which
+    /// point no further reads are necessary.
+    pub fn forward_available(&mut self) -> bool
+    {
+        let mut chunk = [0u8; 4096];
+        loop {
+            match os::read(&self.borrow(), &mut chunk) {
+                Ok(0) => {
+                    // EOF: flush whatever remains unterminated.
+                    self.drain();
+                    return true;
+                },
+                Ok(n) => {
+                    self.partial.extend_from_slice(&chunk[.. n]);
+                    self.flush_lines();
+                },
+                Err(error) => {
+                    // Nothing more to read right now.
+                    if error.raw_os_error() == Some(os::EAGAIN) {
+                        return false;
+                    }
+                    // Any other error ends forwarding.
+                    return true;
+                },
+            }
+        }
+    }
+
+    /// Flush any buffered partial line to the log file.
+    pub fn drain(&mut self)
+    {
+        if !self.partial.is_empty() {
+            self.write_all(&self.partial.clone());
+            self.partial.clear();
+        }
+    }
+
+    /// Write out every complete line, keeping the trailing partial line.
+    fn flush_lines(&mut self)
+    {
+        if let Some(last_newline) = self.partial.iter().rposition(|&b| b == b'\n') {
+            let complete: Vec<u8> = self.partial.drain(.. last_newline + 1).collect();
+            self.write_all(&complete);
+        }
+    }
+
+    /// Write an entire buffer to the log file, retrying after short writes.
+    ///
+    /// Writing stops silently on an error or a zero-length write.
+    fn write_all(&self, mut data: &[u8])
+    {
+        let log = unsafe { BorrowedFd::borrow_raw(self.log_file) };
+        while !data.is_empty() {
+            match os::write(&log, data) {
+                Ok(0)  => break,
+                Ok(n)  => data = &data[n ..],
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Borrow the stderr fd for a read call.
+    fn borrow(&self) -> BorrowedFd
+    {
+        unsafe { BorrowedFd::borrow_raw(self.fd) }
+    }
+}
+
+/// Set `O_NONBLOCK` on a file descriptor.
+pub fn set_nonblocking(fd: RawFd) -> Result<(), io::Error>
+{
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
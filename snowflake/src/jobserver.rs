@@ -0,0 +1,82 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! GNU Make jobserver protocol.
+//!
+//! When Snowflake runs many `run` actions concurrently, each action's build
+//! tool (make, ninja, cargo) would otherwise spin up its own parallelism and
+//! oversubscribe the machine. A jobserver is a pipe whose buffer is preloaded
+//! with `N - 1` single-byte tokens; a tool must [`acquire`](JobServer::acquire)
+//! a token before starting an extra job and [`release`](JobServer::release) it
+//! afterwards. Sharing one jobserver across all containers caps the total
+//! number of in-flight jobs rather than the number per container.
+
+use {
+    snowflake_os as os,
+    std::{
+        io::Result,
+        os::unix::io::{AsRawFd, OwnedFd, RawFd},
+    },
+};
+
+/// A GNU Make jobserver backed by a pipe.
+pub struct JobServer
+{
+    read:  OwnedFd,
+    write: OwnedFd,
+}
+
+impl JobServer
+{
+    /// Create a jobserver permitting `n` concurrent jobs.
+    ///
+    /// The pipe is preloaded with `n - 1` tokens; the implicit token is the
+    /// one every tool holds for its own top-level job.
+    pub fn new(n: usize) -> Result<Self>
+    {
+        let [read, write] = os::pipe2(0)?;
+        let this = Self{read, write};
+        for _ in 1 .. n {
+            this.release()?;
+        }
+        Ok(this)
+    }
+
+    /// Claim a token, blocking until one is available.
+    pub fn acquire(&self) -> Result<()>
+    {
+        let mut token = [0u8; 1];
+        os::read(&self.read, &mut token)?;
+        Ok(())
+    }
+
+    /// Return a token to the pool.
+    pub fn release(&self) -> Result<()>
+    {
+        os::write(&self.write, b"+")?;
+        Ok(())
+    }
+
+    /// File descriptor numbers of the read and write ends.
+    pub fn fds(&self) -> (RawFd, RawFd)
+    {
+        (self.read.as_raw_fd(), self.write.as_raw_fd())
+    }
+
+    /// Value for `MAKEFLAGS` advertising this jobserver to child tools.
+    pub fn makeflags(&self) -> String
+    {
+        let (r, w) = self.fds();
+        makeflags(r, w)
+    }
+}
+
+/// Value for `MAKEFLAGS` advertising a jobserver on the given pipe fds.
+///
+/// Both the modern `--jobserver-auth` and the legacy `--jobserver-fds`
+/// spellings are emitted so that old and new tools alike cooperate. This is
+/// the single source of the format; callers that only hold the raw fds (such
+/// as the run action) use it directly instead of rebuilding the string.
+pub fn makeflags(read: RawFd, write: RawFd) -> String
+{
+    format!("--jobserver-auth={read},{write} --jobserver-fds={read},{write}")
+}
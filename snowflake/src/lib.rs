@@ -12,3 +12,4 @@
 pub mod actions;
 pub mod config;
 pub mod container;
+pub mod jobserver;